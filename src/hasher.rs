@@ -27,4 +27,16 @@ pub trait Hasher {
 
     /// Hash the given data.
     fn hash(data: impl Into<Vec<u8>>) -> Self::Hash;
+
+    /// Byte prepended to leaf data before hashing it.
+    ///
+    /// Tweaking leaf and internal-node hashes with distinct prefixes (domain separation) stops an
+    /// attacker from presenting an internal node's hash as if it were a leaf's. Override this if
+    /// `0x00` collides with another domain already in use by the hash function.
+    const LEAF_PREFIX: u8 = 0x00;
+
+    /// Byte prepended to the concatenated children before hashing them into their parent.
+    ///
+    /// See [`LEAF_PREFIX`](Hasher::LEAF_PREFIX) for why this needs to differ from it.
+    const NODE_PREFIX: u8 = 0x01;
 }
@@ -1,126 +1,434 @@
+use std::io::{self, Read};
+
+use crate::storage::{MemoryStorage, Storage};
 use crate::Hasher;
 
+/// Hash `data` as a leaf, tweaked with [`Hasher::LEAF_PREFIX`] so it cannot be mistaken for an
+/// internal node's hash.
+fn hash_leaf<T: Hasher>(data: impl Into<Vec<u8>>) -> T::Hash {
+    let mut tweaked = vec![T::LEAF_PREFIX];
+    tweaked.extend(data.into());
+    T::hash(tweaked)
+}
+
+/// Hash a `left` and `right` child together into their parent, tweaked with
+/// [`Hasher::NODE_PREFIX`] so it cannot be mistaken for a leaf's hash.
+fn hash_node<T: Hasher>(left: &T::Hash, right: &T::Hash) -> T::Hash {
+    let mut tweaked = vec![T::NODE_PREFIX];
+    tweaked.extend(left.clone().into());
+    tweaked.extend(right.clone().into());
+    T::hash(tweaked)
+}
+
+/// A Merkle inclusion proof for a single leaf.
+///
+/// The proof is the list of sibling hashes encountered on the path from a leaf up to the root,
+/// ordered from the leaf's level to the root's level. Each entry also carries a flag telling
+/// whether the sibling is the right child (`true`) or the left child (`false`) of their shared
+/// parent, so the hashes can be folded back together in the right order during verification.
+///
+/// A `MerkleProof` does not reference the tree it came from, so it can be serialized and sent
+/// to a verifier that only knows the root.
+pub struct MerkleProof<T: Hasher> {
+    /// `(sibling_hash, sibling_is_right)` pairs, from the leaf's level up to the root's level.
+    pub siblings: Vec<(T::Hash, bool)>,
+}
+
 /// A Merkle tree.
 ///
-/// `levels` is a vector of vectors. The first vector contains the root of the tree, the second
-/// vector contains the root's children, and so on. The last vector contains the leaves.
+/// Node hashes live in `storage`, keyed by `(depth, index)` with `depth` counted from the leaves
+/// (`0`) up to the root (`height`); see [`Storage`] for why depth is counted that way. A node
+/// that storage has no entry for is the root of an all-empty subtree, and its value is looked up
+/// in `zero` instead, so an empty or mostly-empty tree costs space and hashing proportional to
+/// the number of leaves actually set rather than to `2^height`.
 ///
-pub struct MerkleTree<T: Hasher> {
-    levels: Vec<Vec<T::Hash>>,
+/// `zero[0]` is the hash of an empty leaf, and `zero[i] = hash(zero[i - 1] || zero[i - 1])` is the
+/// hash of an empty subtree one level taller. `zero[height]` is therefore the root of a
+/// completely empty tree.
+///
+/// `next_leaf` is one past the highest leaf index ever written via [`set_leaves`](Self::set_leaves)
+/// (directly, or through [`insert`](Self::insert)/[`insert_batch`](Self::insert_batch)). It is the
+/// append cursor used by `insert`/`insert_batch` to find "the first empty leaf" — tracking it
+/// explicitly, rather than counting stored leaves, keeps that cursor moving forward even after a
+/// `set_leaves` call that writes past the current end and leaves earlier indices unset.
+/// [`with_storage`](Self::with_storage) recovers it from `storage` itself, so it stays correct
+/// across a restart instead of resetting to `0` and clobbering already-committed leaves.
+pub struct MerkleTree<T: Hasher, S: Storage<T> = MemoryStorage<T>> {
+    height: usize,
+    zero: Vec<T::Hash>,
+    storage: S,
+    next_leaf: usize,
 }
 
-impl<T: Hasher> MerkleTree<T> {
-    /// Create a new Merkle tree with a given height.
+impl<T: Hasher> MerkleTree<T, MemoryStorage<T>> {
+    /// Create a new, empty, in-memory Merkle tree with a given height.
     ///
     /// `height` is the height of the tree. The height of a tree is the number of levels in the
     /// tree, not counting the leaves. For example, a tree with height 2 has 3 levels: the root,
-    /// the root's children, and the leaves. A tree with height 1 has only a root.
+    /// the root's children, and the leaves. A tree with height 0 has only a root, which is also
+    /// its single leaf.
     ///
-    /// The leaves of the tree are empty hashes. The other levels are filled in from the bottom up.
+    /// A tree of height `h` has `2^h` leaves. No leaf or internal node is actually stored until
+    /// it is set; every node starts out resolving to the empty-subtree hash for its level.
     ///
     /// # Examples
     /// ```
-    /// use merkle_tree::MerkleTree;
-    /// let tree: MerkleTree<T> = MerkleTree::new(2);
-    /// assert_eq!(tree.leaf(0), &vec![]);
-    /// assert_eq!(tree.leaf(1), &vec![]);
-    /// assert_eq!(tree.leaf(2), &vec![]);
-    /// assert_eq!(tree.leaf(3), &vec![]);
+    /// use merkle_tree::{Hasher, MerkleTree};
+    ///
+    /// struct NoopHasher;
+    /// impl Hasher for NoopHasher {
+    ///     type Hash = Vec<u8>;
+    ///     fn hash(data: impl Into<Vec<u8>>) -> Self::Hash {
+    ///         data.into()
+    ///     }
+    /// }
+    ///
+    /// let tree: MerkleTree<NoopHasher> = MerkleTree::new(2);
+    /// assert_eq!(tree.leaf(0), vec![0]);
+    /// assert_eq!(tree.leaf(1), vec![0]);
+    /// assert_eq!(tree.leaf(2), vec![0]);
+    /// assert_eq!(tree.leaf(3), vec![0]);
     /// ```
     ///
     pub fn new(height: usize) -> Self {
-        let mut levels = Vec::new();
-
-        // The last level contains the leaves, which are empty hashes.
-        levels.push(vec![T::hash(vec![]); 2 << height]);
-
-        // The other levels are filled in from the bottom up.
-        for i in (0..height).rev() {
-            let mut level: Vec<T::Hash> = Vec::new();
-            for j in 0..(2 << i) {
-                let mut data = Vec::new();
-                data.extend(levels.last().unwrap()[j * 2].clone().into().iter());
-                data.extend(levels.last().unwrap()[j * 2 + 1].clone().into().iter());
-                level.push(data.into());
+        Self::with_storage(height, MemoryStorage::new())
+    }
+
+    /// Build a tree for integrity-checking a byte stream, one leaf per `block_size`-byte block.
+    ///
+    /// `reader` is split into successive chunks of at most `block_size` bytes (the final chunk
+    /// may be shorter); each chunk becomes the data of one leaf, in order. The tree is sized to
+    /// the smallest height that fits every block, then [`insert_batch`](Self::insert_batch) fills
+    /// the leaves in a single pass. Pair the result with [`verify_block`](Self::verify_block) to
+    /// check a single block against the root without re-reading the rest of the stream.
+    pub fn from_reader(mut reader: impl Read, block_size: usize) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut block = vec![0; block_size];
+        loop {
+            let mut filled = 0;
+            while filled < block_size {
+                let read = reader.read(&mut block[filled..])?;
+                if read == 0 {
+                    break;
+                }
+                filled += read;
+            }
+            if filled == 0 {
+                break;
+            }
+            blocks.push(block[..filled].to_vec());
+            if filled < block_size {
+                break;
             }
-            levels.push(level);
         }
 
-        levels.reverse();
-        MerkleTree { levels }
+        let mut height = 0;
+        while (1 << height) < blocks.len() {
+            height += 1;
+        }
+
+        let mut tree = Self::new(height);
+        tree.insert_batch(blocks);
+        Ok(tree)
+    }
+}
+
+impl<T: Hasher, S: Storage<T>> MerkleTree<T, S> {
+    /// Create a new Merkle tree with a given `height`, persisting its node hashes in `storage`
+    /// instead of plain memory. Use this to load a tree that was committed by a previous run, or
+    /// to back a tree too large to keep fully in memory; the append cursor used by
+    /// `insert`/`insert_batch` is recovered from `storage`'s existing leaves, so resuming a
+    /// previous run picks up right after its last leaf instead of overwriting it.
+    pub fn with_storage(height: usize, storage: S) -> Self {
+        // zero[0] is the empty leaf; each further entry is the hash of two copies of the
+        // previous one, i.e. the root of an empty subtree one level taller.
+        let mut zero = Vec::with_capacity(height + 1);
+        zero.push(hash_leaf::<T>(vec![]));
+        for i in 0..height {
+            let empty_child = zero[i].clone();
+            zero.push(hash_node::<T>(&empty_child, &empty_child));
+        }
+
+        let next_leaf = storage.max_index(0).map_or(0, |index| index + 1);
+        MerkleTree { height, zero, storage, next_leaf }
+    }
+
+    /// The hash at `(depth, index)`, falling back to the cached empty-subtree hash if that node
+    /// has never been set.
+    fn node(&self, depth: usize, index: usize) -> T::Hash {
+        self.storage
+            .get(depth, index)
+            .unwrap_or_else(|| self.zero[depth].clone())
     }
 
     /// Get the root of the tree.
-    pub fn root(&self) -> &T::Hash {
-        &self.levels[0][0]
+    pub fn root(&self) -> T::Hash {
+        self.node(self.height, 0)
     }
 
     /// Get the hash of a leaf.
     /// `index` is the index of the leaf.
     /// The leaves are numbered from left to right, starting at 0. For example, if the tree has
     /// height 2, the leaves are numbered 0, 1, 2, and 3.
-    pub fn leaf(&self, index: usize) -> &T::Hash {
-        &self.levels[self.levels.len() - 1][index]
+    pub fn leaf(&self, index: usize) -> T::Hash {
+        self.node(0, index)
     }
 
     /// Insert a new value and recalculate the tree.
     /// `data` is the data to be inserted.
-    /// The data is hashed and inserted into the first empty leaf. If the last level is full,
-    /// the tree is resized. After the data is inserted, the tree is recalculated.
+    /// The data is hashed and inserted into the next leaf past the last one ever written (see
+    /// `next_leaf`), not into the first leaf that happens to be unset — mixing this with
+    /// [`set_leaves`](Self::set_leaves) calls that write past the current end never overwrites an
+    /// index that `set_leaves` has already claimed. If the last level is full, the tree is
+    /// resized. After the data is inserted, the tree is recalculated.
     pub fn insert(&mut self, data: impl Into<Vec<u8>>) {
-        // Find the first empty leaf and insert the data. If the last level is full, resize the
-        // tree.
-        if let Some((mut index, _x)) = self
-            .levels
-            .last()
-            .unwrap()
-            .iter()
+        let index = self.next_leaf;
+        self.set_leaves(&[(index, data.into())]);
+    }
+
+    /// Insert several values, hashing and recomputing the tree only once for the whole batch.
+    ///
+    /// Each item is hashed into the next leaf past the last one ever written, then the next, and
+    /// so on, exactly as repeated calls to [`insert`](Self::insert) would, but sharing a single
+    /// bottom-up recomputation pass and at most one resize for the whole batch instead of one per
+    /// item.
+    pub fn insert_batch(&mut self, items: impl IntoIterator<Item = impl Into<Vec<u8>>>) {
+        let start = self.next_leaf;
+        let updates: Vec<(usize, Vec<u8>)> = items
+            .into_iter()
             .enumerate()
-            .find(|(_i, x)| x.as_ref().is_empty())
-        {
-            self.levels.last_mut().unwrap()[index] = T::hash(data);
-
-            // Recompute the branch of the tree that contains the new leaf from the bottom up.
-            for i in (0..self.levels.len() - 1).rev() {
-                index /= 2;
-                let mut hash = Vec::new();
-                hash.extend(self.levels[i + 1][index * 2].clone().into().iter());
-                hash.extend(self.levels[i + 1][index * 2 + 1].clone().into().iter());
-                self.levels[i][index] = T::hash(hash);
+            .map(|(i, data)| (start + i, data.into()))
+            .collect();
+        self.set_leaves(&updates);
+    }
+
+    /// Set several leaves by index and recompute the tree exactly once.
+    ///
+    /// `updates` is a list of `(index, data)` pairs; later entries for the same index win. All
+    /// the new leaf hashes are written first, then each level above is recomputed bottom-up over
+    /// only the deduplicated set of parent indices touched by the updates, so ancestors shared by
+    /// several updated leaves are re-hashed once rather than once per leaf. The tree is grown
+    /// ahead of time if an update targets an index beyond the current capacity.
+    pub fn set_leaves(&mut self, updates: &[(usize, Vec<u8>)]) {
+        let Some(max_index) = updates.iter().map(|(index, _)| *index).max() else {
+            return;
+        };
+        while max_index >= (1 << self.height) {
+            self.grow();
+        }
+        self.next_leaf = self.next_leaf.max(max_index + 1);
+
+        let mut dirty: Vec<usize> = Vec::with_capacity(updates.len());
+        for (index, data) in updates {
+            self.storage.set(0, *index, hash_leaf::<T>(data.clone()));
+            dirty.push(*index);
+        }
+
+        // Recompute each level exactly once, bottom-up, over only the dirty parent indices.
+        for depth in 1..=self.height {
+            let mut parents: Vec<usize> = dirty.iter().map(|index| index / 2).collect();
+            parents.sort_unstable();
+            parents.dedup();
+
+            for &index in &parents {
+                let left = self.node(depth - 1, index * 2);
+                let right = self.node(depth - 1, index * 2 + 1);
+                self.storage.set(depth, index, hash_node::<T>(&left, &right));
             }
-        } else {
-            self.reseize_and_insert(data);
+            dirty = parents;
         }
     }
 
-    /// Resize the tree and insert a new value.
-    /// `data` is the data to be inserted.
+    /// Grow the tree by one level, doubling its capacity.
     ///
-    /// The tree is resized by adding a new level to the top of the tree and doubling the number
-    /// of leaves. The new leaf is inserted into the first empty leaf. The tree is then recalculated
-    /// from the bottom up.
-    fn reseize_and_insert(&mut self, data: impl Into<Vec<u8>>) {
-        // Add a new level to the tree on the top
-        self.levels.insert(0, vec![]);
-        self.levels.last_mut().unwrap().push(T::hash(data));
-
-        let len = self.levels.last().unwrap().len();
-        self.levels
-            .last_mut()
-            .unwrap()
-            .resize(2 * len, T::Hash::from(vec![]));
-
-        // The starting index of the nodes that need to be recomputed.
-        let mut index = len / 2;
-        // Recompute the new branches of the tree from the bottom up.
-        for i in (0..self.levels.len() - 1).rev() {
-            for j in index..(2 << i) {
-                let mut hash = Vec::new();
-                hash.extend(self.levels[i + 1][j * 2].clone().into().iter());
-                hash.extend(self.levels[i + 1][j * 2 + 1].clone().into().iter());
-                self.levels[i][j] = T::hash(hash);
-            }
+    /// The new root sits one depth higher than before; because depth is counted from the leaves,
+    /// every node already in storage keeps the same `(depth, index)` key it had before growing.
+    /// A node that was absent before (and so resolved to a zero hash) still resolves to the same
+    /// hash afterwards, since `zero` grows by exactly the entry needed for the new height.
+    fn grow(&mut self) {
+        let old_root_zero = self.zero[self.height].clone();
+        self.zero.push(hash_node::<T>(&old_root_zero, &old_root_zero));
+        self.height += 1;
+    }
+
+    /// Build an inclusion proof for the leaf at `index`.
+    ///
+    /// The proof contains the sibling hash at every level between the leaf and the root, along
+    /// with whether that sibling is the right child of their shared parent. Pass the result to
+    /// [`verify`] together with the root and the leaf hash to check membership without access to
+    /// the rest of the tree.
+    pub fn proof(&self, index: usize) -> MerkleProof<T> {
+        let mut siblings = Vec::new();
+        let mut index = index;
+        for depth in 0..self.height {
+            let sibling_is_right = index & 1 == 0;
+            let sibling_index = if sibling_is_right { index + 1 } else { index - 1 };
+            siblings.push((self.node(depth, sibling_index), sibling_is_right));
             index /= 2;
         }
+        MerkleProof { siblings }
+    }
+
+    /// Check whether `block` is the data behind the leaf at `index`, as built by
+    /// [`MerkleTree::from_reader`].
+    ///
+    /// `block` is re-hashed the same way a leaf is hashed on insertion, then checked both against
+    /// the stored leaf and, via an inclusion proof, against the root, so a corrupt region of a
+    /// large file can be localized to the one block that fails without re-reading the rest of it.
+    pub fn verify_block(&self, index: usize, block: &[u8]) -> bool {
+        let leaf = hash_leaf::<T>(block.to_vec());
+        if leaf.as_ref() != self.leaf(index).as_ref() {
+            return false;
+        }
+        verify::<T>(&self.root(), index, &leaf, &self.proof(index))
+    }
+}
+
+/// Verify that `leaf` is the leaf at `index` under `root`, using an inclusion `proof` produced by
+/// [`MerkleTree::proof`].
+///
+/// The leaf is folded together with each sibling hash in turn, and the resulting hash is compared
+/// against `root`. The left/right order at each level is derived from `index` itself rather than
+/// trusting `proof`'s stored `sibling_is_right` bits, so a proof built for one index cannot be
+/// replayed as if it were for another: a caller-supplied `index` that doesn't match the proof it
+/// came with folds the hashes in the wrong order (or leaves a nonzero remainder once the proof is
+/// exhausted) and fails to reproduce `root`.
+pub fn verify<T: Hasher>(
+    root: &T::Hash,
+    index: usize,
+    leaf: &T::Hash,
+    proof: &MerkleProof<T>,
+) -> bool {
+    let mut hash = leaf.clone();
+    let mut index = index;
+    for (sibling, _) in &proof.siblings {
+        hash = if index & 1 == 0 {
+            hash_node::<T>(&hash, sibling)
+        } else {
+            hash_node::<T>(sibling, &hash)
+        };
+        index /= 2;
+    }
+    index == 0 && hash.into().as_slice() == root.clone().into().as_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestHasher;
+    impl Hasher for TestHasher {
+        type Hash = Vec<u8>;
+        fn hash(data: impl Into<Vec<u8>>) -> Self::Hash {
+            data.into()
+        }
+    }
+
+    #[test]
+    fn proof_verifies_against_its_own_index() {
+        let mut tree: MerkleTree<TestHasher> = MerkleTree::new(3);
+        tree.insert_batch([vec![1], vec![2], vec![3], vec![4]]);
+        let root = tree.root();
+
+        for index in 0..4 {
+            let leaf = tree.leaf(index);
+            let proof = tree.proof(index);
+            assert!(verify::<TestHasher>(&root, index, &leaf, &proof));
+        }
+    }
+
+    #[test]
+    fn proof_is_rejected_for_a_different_index() {
+        let mut tree: MerkleTree<TestHasher> = MerkleTree::new(3);
+        tree.insert_batch([vec![1], vec![2], vec![3], vec![4]]);
+        let root = tree.root();
+        let leaf0 = tree.leaf(0);
+        let proof0 = tree.proof(0);
+
+        assert!(verify::<TestHasher>(&root, 0, &leaf0, &proof0));
+        assert!(!verify::<TestHasher>(&root, 5, &leaf0, &proof0));
+    }
+
+    #[test]
+    fn insert_batch_matches_sequential_inserts() {
+        let mut batched: MerkleTree<TestHasher> = MerkleTree::new(3);
+        batched.insert_batch([vec![1], vec![2], vec![3]]);
+
+        let mut sequential: MerkleTree<TestHasher> = MerkleTree::new(3);
+        sequential.insert(vec![1]);
+        sequential.insert(vec![2]);
+        sequential.insert(vec![3]);
+
+        assert_eq!(batched.root(), sequential.root());
+    }
+
+    #[test]
+    fn insert_never_reuses_a_hole_left_by_set_leaves() {
+        let mut tree: MerkleTree<TestHasher> = MerkleTree::new(3);
+        tree.set_leaves(&[(5, vec![9])]);
+        tree.insert(vec![2]);
+
+        // Index 0 is still genuinely empty; `insert` must not mistake it for "the next free
+        // leaf" just because it has never been set.
+        assert_eq!(tree.leaf(0), hash_leaf::<TestHasher>(vec![]));
+        assert_eq!(tree.leaf(6), hash_leaf::<TestHasher>(vec![2]));
+    }
+
+    #[test]
+    fn memory_storage_reads_back_what_it_was_given() {
+        let mut storage: MemoryStorage<TestHasher> = MemoryStorage::new();
+        assert_eq!(storage.get(1, 0), None);
+
+        storage.set(1, 0, vec![42]);
+        assert_eq!(storage.get(1, 0), Some(vec![42]));
+        assert_eq!(storage.len(1), 1);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn reopening_storage_resumes_the_append_cursor_instead_of_overwriting() {
+        use crate::storage::SledStorage;
+
+        let path = std::env::temp_dir().join(format!(
+            "merkle-tree-test-{}-{}",
+            std::process::id(),
+            "reopen_resumes_append_cursor"
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+
+        {
+            let storage = SledStorage::<TestHasher>::open(&path).unwrap();
+            let mut tree: MerkleTree<TestHasher, SledStorage<TestHasher>> =
+                MerkleTree::with_storage(3, storage);
+            tree.insert_batch([vec![1], vec![2], vec![3]]);
+        } // simulate process exit: the tree and its sled handle are dropped here.
+
+        let storage = SledStorage::<TestHasher>::open(&path).unwrap();
+        let mut tree: MerkleTree<TestHasher, SledStorage<TestHasher>> =
+            MerkleTree::with_storage(3, storage);
+        tree.insert(vec![4]);
+
+        // The leaves committed before the restart must survive, and the new leaf must land
+        // right after them rather than clobbering index 0.
+        assert_eq!(tree.leaf(0), hash_leaf::<TestHasher>(vec![1]));
+        assert_eq!(tree.leaf(1), hash_leaf::<TestHasher>(vec![2]));
+        assert_eq!(tree.leaf(2), hash_leaf::<TestHasher>(vec![3]));
+        assert_eq!(tree.leaf(3), hash_leaf::<TestHasher>(vec![4]));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn from_reader_splits_into_blocks_and_verify_block_detects_tampering() {
+        let data = b"hello world, this is more than one block".to_vec();
+        let block_size = 8;
+        let tree = MerkleTree::<TestHasher>::from_reader(&data[..], block_size).unwrap();
+
+        for (index, block) in data.chunks(block_size).enumerate() {
+            assert!(tree.verify_block(index, block));
+        }
+
+        assert!(!tree.verify_block(0, b"tampered"));
+        assert!(!tree.verify_block(1, &data[0..block_size]));
     }
 }
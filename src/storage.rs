@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::Hasher;
+
+/// Persistent storage for the node hashes of a [`MerkleTree`](crate::MerkleTree), keyed by
+/// `(depth, index)`, where `depth` counts up from the leaves (`0`) to the root (the tree's
+/// height).
+///
+/// A node missing from storage is not an error: it is the root of a subtree that has never been
+/// written, and the tree falls back to its cached empty-subtree hash for that depth. Because
+/// depth counts from the leaves, growing the tree only ever adds nodes at a new, larger depth —
+/// existing entries never need to be renumbered or moved, which is what makes it safe to back
+/// this trait with storage that is expensive to rewrite, such as a database.
+pub trait Storage<T: Hasher> {
+    /// Look up the hash stored at `(depth, index)`, if any.
+    fn get(&self, depth: usize, index: usize) -> Option<T::Hash>;
+
+    /// Store `hash` at `(depth, index)`, overwriting any previous value.
+    fn set(&mut self, depth: usize, index: usize, hash: T::Hash);
+
+    /// The number of nodes stored at `depth`.
+    fn len(&self, depth: usize) -> usize;
+
+    /// The largest index ever set at `depth`, if any.
+    ///
+    /// [`MerkleTree::with_storage`](crate::MerkleTree::with_storage) uses this at depth `0` to
+    /// recover the append cursor when reopening storage from a previous run, so it must reflect
+    /// every index ever written at that depth, not just how many are currently stored.
+    fn max_index(&self, depth: usize) -> Option<usize>;
+}
+
+/// An in-memory [`Storage`] backed by one hash map per depth. This is the default storage for
+/// [`MerkleTree`](crate::MerkleTree) and does not survive a restart.
+pub struct MemoryStorage<T: Hasher> {
+    depths: Vec<HashMap<usize, T::Hash>>,
+}
+
+impl<T: Hasher> MemoryStorage<T> {
+    /// Create an empty in-memory storage.
+    pub fn new() -> Self {
+        MemoryStorage { depths: Vec::new() }
+    }
+}
+
+impl<T: Hasher> Default for MemoryStorage<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Hasher> Storage<T> for MemoryStorage<T> {
+    fn get(&self, depth: usize, index: usize) -> Option<T::Hash> {
+        self.depths.get(depth)?.get(&index).cloned()
+    }
+
+    fn set(&mut self, depth: usize, index: usize, hash: T::Hash) {
+        if depth >= self.depths.len() {
+            self.depths.resize_with(depth + 1, HashMap::new);
+        }
+        self.depths[depth].insert(index, hash);
+    }
+
+    fn len(&self, depth: usize) -> usize {
+        self.depths.get(depth).map_or(0, HashMap::len)
+    }
+
+    fn max_index(&self, depth: usize) -> Option<usize> {
+        self.depths.get(depth)?.keys().copied().max()
+    }
+}
+
+/// A [`Storage`] backed by a [`sled`] database, for trees that must survive a restart or are too
+/// large to keep fully in memory. Nodes are loaded and written one at a time as the tree needs
+/// them, so construction and queries stay cheap regardless of how much of the tree has been
+/// committed in previous runs.
+///
+/// `Storage::get`/`set` are infallible by trait design, but the underlying database operations
+/// are not: `get`/`set` panic if sled reports an I/O error (disk full, permission denied, on-disk
+/// corruption, and the like). This is only ever the local database misbehaving, never a property
+/// of the tree's own data, so callers who need to keep running in the face of it should treat the
+/// database itself as the thing to make redundant (e.g. replicate it), not catch the panic.
+#[cfg(feature = "sled")]
+pub struct SledStorage<T: Hasher> {
+    tree: sled::Tree,
+    _hasher: std::marker::PhantomData<T>,
+}
+
+#[cfg(feature = "sled")]
+impl<T: Hasher> SledStorage<T> {
+    /// Open (or create) a sled database at `path` to store node hashes in.
+    pub fn open(path: impl AsRef<std::path::Path>) -> sled::Result<Self> {
+        let tree = sled::open(path)?.open_tree("merkle_tree")?;
+        Ok(SledStorage { tree, _hasher: std::marker::PhantomData })
+    }
+
+    /// Encode `(depth, index)` as a sortable, fixed-width sled key.
+    fn key(depth: usize, index: usize) -> [u8; 16] {
+        let mut key = [0; 16];
+        key[..8].copy_from_slice(&(depth as u64).to_be_bytes());
+        key[8..].copy_from_slice(&(index as u64).to_be_bytes());
+        key
+    }
+}
+
+#[cfg(feature = "sled")]
+impl<T: Hasher> Storage<T> for SledStorage<T> {
+    /// # Panics
+    ///
+    /// Panics if the underlying sled database returns an I/O error.
+    fn get(&self, depth: usize, index: usize) -> Option<T::Hash> {
+        self.tree
+            .get(Self::key(depth, index))
+            .expect("sled get failed")
+            .map(|bytes| T::Hash::from(bytes.to_vec()))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the underlying sled database returns an I/O error.
+    fn set(&mut self, depth: usize, index: usize, hash: T::Hash) {
+        self.tree
+            .insert(Self::key(depth, index), hash.into())
+            .expect("sled insert failed");
+    }
+
+    fn len(&self, depth: usize) -> usize {
+        let prefix = (depth as u64).to_be_bytes();
+        self.tree.scan_prefix(prefix).count()
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the underlying sled database returns an I/O error.
+    fn max_index(&self, depth: usize) -> Option<usize> {
+        let prefix = (depth as u64).to_be_bytes();
+        let (key, _) = self
+            .tree
+            .scan_prefix(prefix)
+            .last()?
+            .expect("sled scan failed");
+        let index_bytes: [u8; 8] = key[8..16].try_into().unwrap();
+        Some(u64::from_be_bytes(index_bytes) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestHasher;
+    impl Hasher for TestHasher {
+        type Hash = Vec<u8>;
+        fn hash(data: impl Into<Vec<u8>>) -> Self::Hash {
+            data.into()
+        }
+    }
+
+    #[test]
+    fn memory_storage_max_index_tracks_the_highest_index_set() {
+        let mut storage: MemoryStorage<TestHasher> = MemoryStorage::new();
+        assert_eq!(storage.max_index(0), None);
+
+        storage.set(0, 3, vec![1]);
+        storage.set(0, 1, vec![2]);
+        assert_eq!(storage.max_index(0), Some(3));
+        assert_eq!(storage.max_index(1), None);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn sled_storage_round_trips_across_separate_handles() {
+        let path = std::env::temp_dir().join(format!(
+            "merkle-tree-test-{}-{}",
+            std::process::id(),
+            "sled_storage_round_trips"
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+
+        {
+            let mut storage = SledStorage::<TestHasher>::open(&path).unwrap();
+            storage.set(0, 0, vec![1]);
+            storage.set(0, 2, vec![3]);
+        } // simulate process exit: this handle is dropped before reopening.
+
+        let storage = SledStorage::<TestHasher>::open(&path).unwrap();
+        assert_eq!(storage.get(0, 0), Some(vec![1]));
+        assert_eq!(storage.get(0, 2), Some(vec![3]));
+        assert_eq!(storage.get(0, 1), None);
+        assert_eq!(storage.len(0), 2);
+        assert_eq!(storage.max_index(0), Some(2));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}